@@ -0,0 +1,440 @@
+//! Incremental transitive inclusion, like resolving `#include`s in shader sources.
+
+use crate::{Current, Dependee, LastComputed, LastModified, LastVerified, Revision};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+pub type SourceIndex = usize;
+
+type EntryPointIndex = usize;
+
+#[derive(Debug, Clone)]
+pub enum Token<K, L> {
+    Literal(L),
+    Include(K),
+}
+
+#[derive(Debug)]
+struct Source<K, L> {
+    key: K,
+    last_modified: LastModified,
+    last_computed: LastComputed,
+    tokens: Vec<Token<K, L>>,
+    // The entry points whose last computation included this source.
+    included_by: Vec<EntryPointIndex>,
+}
+
+/// The sources known so far, keyed by `K` and holding literals of type `L`.
+#[derive(Debug)]
+pub struct IncludeGraph<K, L> {
+    key_to_source_index: HashMap<K, SourceIndex>,
+    sources: Vec<Source<K, L>>,
+    // Per entry point, the most recent revision of the sources it included.
+    entry_points: Vec<Revision>,
+}
+
+impl<K: Clone + Eq + Hash, L> IncludeGraph<K, L> {
+    pub fn new() -> Self {
+        Self {
+            key_to_source_index: HashMap::new(),
+            sources: Vec::new(),
+            entry_points: Vec::new(),
+        }
+    }
+
+    /// Looks up the source for `key`, adding it as not yet read if it is new.
+    pub fn source_index(&mut self, current: &Current, key: K) -> SourceIndex {
+        match self.key_to_source_index.get(&key) {
+            Some(&source_index) => source_index,
+            None => {
+                let source_index = self.sources.len();
+                self.sources.push(Source {
+                    key: key.clone(),
+                    last_modified: LastModified::new(current),
+                    last_computed: LastComputed::dirty(),
+                    tokens: Vec::new(),
+                    included_by: Vec::new(),
+                });
+                self.key_to_source_index.insert(key, source_index);
+                source_index
+            }
+        }
+    }
+
+    pub fn key(&self, source_index: SourceIndex) -> &K {
+        &self.sources[source_index].key
+    }
+
+    /// Marks the source as changed so it is read again by the next entry point update. Only the
+    /// entry points that included the source recompute.
+    pub fn modify(&mut self, current: &mut Current, source_index: SourceIndex) {
+        let source = &mut self.sources[source_index];
+        source.last_modified.modify(current);
+        for &entry_point in &source.included_by {
+            self.entry_points[entry_point].merge(source.last_modified.revision());
+        }
+    }
+
+    fn register(&mut self, entry_point: EntryPointIndex, source_index: SourceIndex) {
+        let source = &mut self.sources[source_index];
+        source.included_by.push(entry_point);
+        self.entry_points[entry_point].merge(source.last_modified.revision());
+    }
+
+    fn unregister(&mut self, entry_point: EntryPointIndex, included: &[SourceIndex]) {
+        for &source_index in included {
+            self.sources[source_index]
+                .included_by
+                .retain(|&other| other != entry_point);
+        }
+        self.entry_points[entry_point] = Revision::DIRTY;
+    }
+
+    fn update_source(
+        &mut self,
+        source_index: SourceIndex,
+        read: &mut impl FnMut(&K, &mut Vec<Token<K, L>>),
+    ) {
+        let source = &mut self.sources[source_index];
+        if source.last_computed.should_compute(&source.last_modified) {
+            source.last_computed.update_to(&source.last_modified);
+            read(&source.key, &mut source.tokens);
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, L> Default for IncludeGraph<K, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A source that includes itself, directly or through other sources. The path starts and ends
+/// with the same key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IncludeCycle<K> {
+    pub path: Vec<K>,
+}
+
+impl<K: fmt::Debug> fmt::Display for IncludeCycle<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Include cycle detected: ")?;
+        for (i, key) in self.path.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: fmt::Debug> std::error::Error for IncludeCycle<K> {}
+
+/// The transitive inclusion of a root source, flattened into `T`.
+#[derive(Debug)]
+pub struct EntryPoint<T> {
+    index: EntryPointIndex,
+    source_index: SourceIndex,
+    last_verified: LastVerified,
+    last_computed: LastComputed,
+    output: T,
+    included: Vec<SourceIndex>,
+}
+
+enum Presence {
+    Unique,
+    Duplicate,
+}
+
+fn vec_set_add<T: Copy + PartialEq>(vec: &mut Vec<T>, val: T) -> Presence {
+    if vec.contains(&val) {
+        Presence::Duplicate
+    } else {
+        vec.push(val);
+        Presence::Unique
+    }
+}
+
+impl<T: Default> EntryPoint<T> {
+    /// Registers a new entry point with `graph`, which tracks the sources it includes.
+    pub fn new<K: Clone + Eq + Hash, L>(
+        graph: &mut IncludeGraph<K, L>,
+        source_index: SourceIndex,
+    ) -> Self {
+        let index = graph.entry_points.len();
+        graph.entry_points.push(Revision::DIRTY);
+        graph.register(index, source_index);
+        Self {
+            index,
+            source_index,
+            last_verified: LastVerified::dirty(),
+            last_computed: LastComputed::dirty(),
+            output: T::default(),
+            included: vec![source_index],
+        }
+    }
+
+    pub fn source_index(&self) -> SourceIndex {
+        self.source_index
+    }
+
+    pub fn output(&self) -> &T {
+        &self.output
+    }
+
+    /// The sources included by the last computation in include order, without duplicates.
+    pub fn included(&self) -> &[SourceIndex] {
+        &self.included
+    }
+
+    /// Recomputes the output if any of the included sources was modified. Sources are read with
+    /// `read` when they changed and each literal is passed to `emit` in include order. Returns
+    /// whether the output was recomputed.
+    ///
+    /// A source included twice is only emitted the first time, but a source that includes
+    /// itself is an error. After an error the output is reset to `T::default()` and the entry
+    /// point stays dirty, reporting the cycle again until it is resolved.
+    pub fn update<K: Clone + Eq + Hash, L>(
+        &mut self,
+        current: &Current,
+        graph: &mut IncludeGraph<K, L>,
+        mut read: impl FnMut(&K, &mut Vec<Token<K, L>>),
+        mut emit: impl FnMut(&mut T, &L),
+    ) -> Result<bool, IncludeCycle<K>> {
+        if self.last_verified.should_verify(current) {
+            self.last_verified.update_to(current);
+        } else {
+            return Ok(false);
+        }
+
+        // The graph tracks the included sources, so there is no need to scan them.
+        let should_recompute = self.last_computed.revision() < graph.entry_points[self.index];
+
+        if should_recompute {
+            self.output = T::default();
+            graph.unregister(self.index, &self.included);
+            self.included.clear();
+
+            let source_index = self.source_index;
+            let mut stack = Vec::new();
+            if let Err(cycle) = self.process(
+                current,
+                graph,
+                &mut read,
+                &mut emit,
+                &mut stack,
+                source_index,
+            ) {
+                self.last_verified = LastVerified::dirty();
+                self.last_computed = LastComputed::dirty();
+                self.output = T::default();
+                graph.unregister(self.index, &self.included);
+                self.included = vec![self.source_index];
+                graph.register(self.index, self.source_index);
+                return Err(cycle);
+            }
+        }
+
+        Ok(should_recompute)
+    }
+
+    fn process<K: Clone + Eq + Hash, L>(
+        &mut self,
+        current: &Current,
+        graph: &mut IncludeGraph<K, L>,
+        read: &mut impl FnMut(&K, &mut Vec<Token<K, L>>),
+        emit: &mut impl FnMut(&mut T, &L),
+        stack: &mut Vec<SourceIndex>,
+        source_index: SourceIndex,
+    ) -> Result<(), IncludeCycle<K>> {
+        if let Some(position) = stack.iter().position(|&active| active == source_index) {
+            let path = stack[position..]
+                .iter()
+                .chain(Some(&source_index))
+                .map(|&active| graph.key(active).clone())
+                .collect();
+            return Err(IncludeCycle { path });
+        }
+
+        // Stop processing if we've already included this source.
+        if let Presence::Duplicate = vec_set_add(&mut self.included, source_index) {
+            return Ok(());
+        }
+
+        stack.push(source_index);
+
+        graph.update_source(source_index, read);
+        graph.register(self.index, source_index);
+
+        self.last_computed
+            .merge(&graph.sources[source_index].last_modified);
+
+        // Index the tokens so the graph can grow while we recurse into includes.
+        for token_index in 0..graph.sources[source_index].tokens.len() {
+            match graph.sources[source_index].tokens[token_index] {
+                Token::Literal(ref literal) => {
+                    emit(&mut self.output, literal);
+                }
+                Token::Include(ref key) => {
+                    let key = key.clone();
+                    let included_index = graph.source_index(current, key);
+                    self.process(current, graph, read, emit, stack, included_index)?;
+                }
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sources = HashMap<&'static str, Vec<Token<&'static str, &'static str>>>;
+
+    /// Parses whitespace separated tokens, `#key` includes `key`.
+    fn source(tokens: &'static str) -> Vec<Token<&'static str, &'static str>> {
+        tokens
+            .split_whitespace()
+            .map(|token| match token.strip_prefix('#') {
+                Some(key) => Token::Include(key),
+                None => Token::Literal(token),
+            })
+            .collect()
+    }
+
+    fn update(
+        current: &Current,
+        graph: &mut IncludeGraph<&'static str, &'static str>,
+        entry_point: &mut EntryPoint<String>,
+        sources: &Sources,
+        reads: &mut Vec<&'static str>,
+    ) -> Result<bool, IncludeCycle<&'static str>> {
+        entry_point.update(
+            current,
+            graph,
+            |&key, tokens| {
+                reads.push(key);
+                tokens.clone_from(&sources[key]);
+            },
+            |output, literal| output.push_str(literal),
+        )
+    }
+
+    #[test]
+    fn diamond_includes_are_emitted_once() {
+        let mut current = Current::new();
+        let mut sources = Sources::new();
+        sources.insert("a", source("a #b #c"));
+        sources.insert("b", source("b #d"));
+        sources.insert("c", source("c #d"));
+        sources.insert("d", source("d"));
+
+        let mut graph = IncludeGraph::new();
+        let a = graph.source_index(&current, "a");
+        let mut entry_point = EntryPoint::new(&mut graph, a);
+        let mut reads = Vec::new();
+
+        assert!(update(&current, &mut graph, &mut entry_point, &sources, &mut reads).unwrap());
+        assert_eq!(entry_point.output(), "abdc");
+        assert_eq!(reads, ["a", "b", "d", "c"]);
+        let included: Vec<_> = entry_point
+            .included()
+            .iter()
+            .map(|&index| *graph.key(index))
+            .collect();
+        assert_eq!(included, ["a", "b", "d", "c"]);
+
+        reads.clear();
+        sources.insert("d", source("D"));
+        let d = graph.source_index(&current, "d");
+        graph.modify(&mut current, d);
+        assert!(update(&current, &mut graph, &mut entry_point, &sources, &mut reads).unwrap());
+        assert_eq!(entry_point.output(), "abDc");
+        assert_eq!(reads, ["d"]);
+    }
+
+    #[test]
+    fn include_cycles_are_reported_until_resolved() {
+        let mut current = Current::new();
+        let mut sources = Sources::new();
+        sources.insert("a", source("a #b"));
+        sources.insert("b", source("b"));
+
+        let mut graph = IncludeGraph::new();
+        let a = graph.source_index(&current, "a");
+        let mut entry_point = EntryPoint::new(&mut graph, a);
+        let mut reads = Vec::new();
+        update(&current, &mut graph, &mut entry_point, &sources, &mut reads).unwrap();
+        assert_eq!(entry_point.output(), "ab");
+
+        sources.insert("b", source("b #a"));
+        let b = graph.source_index(&current, "b");
+        graph.modify(&mut current, b);
+        let cycle = update(&current, &mut graph, &mut entry_point, &sources, &mut reads);
+        let cycle = cycle.unwrap_err();
+        assert_eq!(cycle.path, ["a", "b", "a"]);
+        assert_eq!(
+            cycle.to_string(),
+            r#"Include cycle detected: "a" -> "b" -> "a""#
+        );
+        assert_eq!(entry_point.output(), "");
+        assert_eq!(entry_point.included(), [a]);
+
+        // Still reported after Current advanced for an unrelated reason.
+        LastModified::new(&current).modify(&mut current);
+        assert!(update(&current, &mut graph, &mut entry_point, &sources, &mut reads).is_err());
+
+        sources.insert("b", source("b"));
+        graph.modify(&mut current, b);
+        assert!(update(&current, &mut graph, &mut entry_point, &sources, &mut reads).unwrap());
+        assert_eq!(entry_point.output(), "ab");
+    }
+
+    #[test]
+    fn only_entry_points_including_a_modified_source_recompute() {
+        let mut current = Current::new();
+        let mut sources = Sources::new();
+        sources.insert("a", source("a #common #mode"));
+        sources.insert("b", source("b #common"));
+        sources.insert("common", source("common"));
+        sources.insert("mode", source("1"));
+
+        let mut graph = IncludeGraph::new();
+        let a = graph.source_index(&current, "a");
+        let b = graph.source_index(&current, "b");
+        let mut entry_a = EntryPoint::new(&mut graph, a);
+        let mut entry_b = EntryPoint::new(&mut graph, b);
+        let mut reads = Vec::new();
+        update(&current, &mut graph, &mut entry_a, &sources, &mut reads).unwrap();
+        update(&current, &mut graph, &mut entry_b, &sources, &mut reads).unwrap();
+        let entry_b_revision = graph.entry_points[entry_b.index];
+
+        sources.insert("mode", source("2"));
+        let mode = graph.source_index(&current, "mode");
+        graph.modify(&mut current, mode);
+        assert_eq!(graph.entry_points[entry_a.index], current.revision());
+        assert_eq!(graph.entry_points[entry_b.index], entry_b_revision);
+
+        reads.clear();
+        assert!(update(&current, &mut graph, &mut entry_a, &sources, &mut reads).unwrap());
+        assert!(!update(&current, &mut graph, &mut entry_b, &sources, &mut reads).unwrap());
+        assert_eq!(entry_a.output(), "acommon2");
+        assert_eq!(entry_b.output(), "bcommon");
+        assert_eq!(reads, ["mode"]);
+
+        // Both recompute after a source they share changes.
+        sources.insert("common", source("COMMON"));
+        let common = graph.source_index(&current, "common");
+        graph.modify(&mut current, common);
+        assert!(update(&current, &mut graph, &mut entry_a, &sources, &mut reads).unwrap());
+        assert!(update(&current, &mut graph, &mut entry_b, &sources, &mut reads).unwrap());
+        assert_eq!(entry_a.output(), "aCOMMON2");
+        assert_eq!(entry_b.output(), "bCOMMON");
+    }
+}
@@ -1,66 +1,544 @@
 //! This is more of a philosophy than a library
 
+pub mod include;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 pub trait Dependee {
     fn revision(&self) -> Revision;
 }
 
-#[derive(Debug)]
-pub struct Current(Revision);
+impl<D: Dependee + ?Sized> Dependee for &D {
+    fn revision(&self) -> Revision {
+        (**self).revision()
+    }
+}
+
+/// The most recent revision of the elements, `Revision::DIRTY` when empty.
+impl<D: Dependee> Dependee for [D] {
+    fn revision(&self) -> Revision {
+        max_revision(self.iter().map(Dependee::revision))
+    }
+}
+
+macro_rules! impl_dependee_for_tuple {
+    ($($D:ident),+) => {
+        /// The most recent revision of the elements.
+        impl<$($D: Dependee),+> Dependee for ($($D,)+) {
+            #[allow(non_snake_case)]
+            fn revision(&self) -> Revision {
+                let ($(ref $D,)+) = *self;
+                let mut revision = Revision::DIRTY;
+                $(revision.merge($D.revision());)+
+                revision
+            }
+        }
+    };
+}
+
+impl_dependee_for_tuple!(A);
+impl_dependee_for_tuple!(A, B);
+impl_dependee_for_tuple!(A, B, C);
+impl_dependee_for_tuple!(A, B, C, D);
+impl_dependee_for_tuple!(A, B, C, D, E);
+impl_dependee_for_tuple!(A, B, C, D, E, F);
+impl_dependee_for_tuple!(A, B, C, D, E, F, G);
+impl_dependee_for_tuple!(A, B, C, D, E, F, G, H);
+
+pub struct Current {
+    revision: Revision,
+    strategy: Box<dyn AdvanceStrategy + Send + Sync>,
+    observers: Vec<Box<dyn FnMut(Revision) + Send + Sync>>,
+}
 
 impl Current {
     pub fn new() -> Self {
-        Self(Revision::INITIAL_CURRENT)
+        Self::with_strategy(Increment)
+    }
+
+    /// Creates a `Current` that asks `strategy` for the next revision on every modification.
+    pub fn with_strategy(strategy: impl AdvanceStrategy + Send + Sync + 'static) -> Self {
+        Self {
+            revision: Revision::INITIAL_CURRENT,
+            strategy: Box::new(strategy),
+            observers: Vec::new(),
+        }
+    }
+
+    /// Continues after loading persisted revisions, so the current revision is never behind any
+    /// of them. Pass every loaded revision, including those of `LastVerified`.
+    pub fn restored(mut self, loaded: impl IntoIterator<Item = Revision>) -> Self {
+        self.revision.merge(max_revision(loaded));
+        self
+    }
+
+    /// Calls `observer` with the new revision every time this `Current` advances, e.g. to
+    /// schedule a repaint. A batch advances, and so notifies, at most once.
+    pub fn on_advance(&mut self, observer: Box<dyn FnMut(Revision) + Send + Sync>) {
+        self.observers.push(observer);
+    }
+
+    /// Runs `f` with a `Batch` that stamps every modification with the same revision, so
+    /// `Current` advances at most once for the whole batch.
+    pub fn batch<R>(&mut self, f: impl FnOnce(&mut Batch) -> R) -> R {
+        f(&mut Batch {
+            current: self,
+            advanced: false,
+        })
+    }
+
+    pub fn snapshot(&self) -> RevisionSnapshot {
+        RevisionSnapshot(self.revision)
+    }
+
+    /// Starts undoing the modifications made since `snapshot`. Revisions are never handed out
+    /// twice, so this advances to a new revision rather than moving back. Put back the value of
+    /// every leaf modified since the snapshot and call `LastModified::rollback` on it before
+    /// verifying; nodes need no rollback, the restored leaves make them recompute.
+    pub fn restore(&mut self, snapshot: RevisionSnapshot) -> Restore {
+        debug_assert!(
+            snapshot.0 <= self.revision,
+            "Current::restore: snapshot {:?} is ahead of current {:?}",
+            snapshot.0,
+            self.revision
+        );
+        Restore {
+            snapshot: snapshot.0,
+            revision: self.advance(),
+        }
+    }
+
+    fn advance(&mut self) -> Revision {
+        let next = self.strategy.advance(self.revision.0);
+        self.advance_to(Revision(next))
+    }
+
+    fn advance_to(&mut self, next: Revision) -> Revision {
+        assert!(
+            next > self.revision,
+            "AdvanceStrategy::advance: next revision {} is not ahead of current {}",
+            next.0,
+            self.revision.0
+        );
+        self.revision = next;
+        for observer in &mut self.observers {
+            observer(next);
+        }
+        next
+    }
+}
+
+/// Depending on `Current` itself makes a node time-varying: it recomputes once every time the
+/// revision advances, like an animation keyed off the frame counter.
+impl Dependee for Current {
+    fn revision(&self) -> Revision {
+        self.revision
+    }
+}
+
+impl std::fmt::Debug for Current {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Current")
+            .field("revision", &self.revision)
+            .finish()
     }
 }
 
+impl Default for Current {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RevisionSnapshot(Revision);
+
+/// Returned by `Current::restore`, pass it to `LastModified::rollback`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Restore {
+    snapshot: Revision,
+    revision: Revision,
+}
+
+#[derive(Debug)]
+pub struct Batch<'a> {
+    current: &'a mut Current,
+    advanced: bool,
+}
+
+impl Batch<'_> {
+    pub fn modify(&mut self, last_modified: &mut LastModified) {
+        debug_assert!(
+            last_modified.0 <= self.current.revision,
+            "Batch::modify: last modified {:?} is ahead of current {:?}",
+            last_modified.0,
+            self.current.revision
+        );
+        if !self.advanced {
+            self.current.advance();
+            self.advanced = true;
+        }
+        last_modified.0 = self.current.revision;
+    }
+}
+
+/// A `Current` that can be shared between threads. It always advances by one, there is no
+/// `AdvanceStrategy` to consult nor observer to call without a lock.
+#[derive(Debug)]
+pub struct AtomicCurrent(AtomicU64);
+
+impl AtomicCurrent {
+    pub fn new() -> Self {
+        Self(AtomicU64::new(Revision::INITIAL_CURRENT.0))
+    }
+
+    pub fn revision(&self) -> Revision {
+        Revision(self.0.load(Ordering::SeqCst))
+    }
+
+    /// Takes a `Current` at the present revision, for verifying nodes owned by this thread.
+    pub fn load(&self) -> LoadedCurrent {
+        LoadedCurrent(Current {
+            revision: self.revision(),
+            ..Current::new()
+        })
+    }
+
+    pub fn modify(&self, last_modified: &mut LastModified) {
+        let previous = self
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |revision| {
+                revision.checked_add(1)
+            })
+            .expect("Revision overflow: cannot advance past Revision::MAX");
+        last_modified.0 = Revision(previous + 1);
+    }
+}
+
+impl Default for AtomicCurrent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A read-only `Current` loaded from an `AtomicCurrent`. Modifications have to go through the
+/// `AtomicCurrent`, advancing a copy would hand out revisions other threads also use.
+#[derive(Debug)]
+pub struct LoadedCurrent(Current);
+
+impl std::ops::Deref for LoadedCurrent {
+    type Target = Current;
+
+    fn deref(&self) -> &Current {
+        &self.0
+    }
+}
+
+/// Number of recent tagged revisions `TaggedCurrent` remembers.
+const TAG_CAPACITY: usize = 64;
+
+/// A `Current` that associates revisions with external ids such as frame numbers.
+#[derive(Debug, Default)]
+pub struct TaggedCurrent {
+    current: Current,
+    tags: VecDeque<(Revision, u64)>,
+}
+
+impl TaggedCurrent {
+    pub fn new(current: Current) -> Self {
+        Self {
+            current,
+            tags: VecDeque::new(),
+        }
+    }
+
+    pub fn current(&self) -> &Current {
+        &self.current
+    }
+
+    /// For the modifications made under the most recent tag.
+    pub fn current_mut(&mut self) -> &mut Current {
+        &mut self.current
+    }
+
+    /// Advances to a new revision and associates it with `tag`.
+    pub fn advance_with_tag(&mut self, tag: u64) -> Revision {
+        let revision = self.current.advance();
+        if self.tags.len() == TAG_CAPACITY {
+            self.tags.pop_front();
+        }
+        self.tags.push_back((revision, tag));
+        revision
+    }
+
+    /// Looks up the external id in effect at `revision`, that of the most recent tagged revision
+    /// at or before it. A modification made after `advance_with_tag` therefore reports its tag.
+    /// Only the most recent tagged revisions are kept, older revisions return `None`.
+    pub fn tag(&self, revision: Revision) -> Option<u64> {
+        self.tags
+            .iter()
+            .rev()
+            .find(|&&(tagged, _)| tagged <= revision)
+            .map(|&(_, tag)| tag)
+    }
+}
+
+/// A `Current` driven by externally supplied offsets, e.g. from a message queue, instead of by
+/// counting. Offsets start at `0` and must be strictly increasing.
+#[derive(Debug, Default)]
+pub struct OffsetCurrent(Current);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OutOfOrderOffset {
+    pub offset: u64,
+    pub next_offset: u64,
+}
+
+impl std::fmt::Display for OutOfOrderOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "offset {} is behind the next expected offset {}",
+            self.offset, self.next_offset
+        )
+    }
+}
+
+impl std::error::Error for OutOfOrderOffset {}
+
+impl OffsetCurrent {
+    pub fn new() -> Self {
+        Self(Current::new())
+    }
+
+    /// Resumes a replay at `offset`, the offsets before it are considered applied.
+    pub fn resume_at(offset: u64) -> Self {
+        let mut current = Current::new();
+        if offset > 0 {
+            current.revision = Self::revision(offset - 1);
+        }
+        Self(current)
+    }
+
+    // Offset `0` maps to the revision after the initial one.
+    fn revision(offset: u64) -> Revision {
+        Revision(
+            offset
+                .checked_add(Revision::INITIAL_CURRENT.0 + 1)
+                .expect("Revision overflow: offset is too large"),
+        )
+    }
+
+    pub fn current(&self) -> &Current {
+        &self.0
+    }
+
+    /// The smallest offset the next modification may use.
+    pub fn next_offset(&self) -> u64 {
+        self.0.revision.0 - Revision::INITIAL_CURRENT.0
+    }
+
+    pub fn modify(
+        &mut self,
+        last_modified: &mut LastModified,
+        offset: u64,
+    ) -> Result<(), OutOfOrderOffset> {
+        self.batch(offset, |batch| batch.modify(last_modified))
+    }
+
+    /// Runs `f` with an `OffsetBatch` that stamps every modification with `offset`, for a message
+    /// that updates several inputs.
+    pub fn batch<R>(
+        &mut self,
+        offset: u64,
+        f: impl FnOnce(&mut OffsetBatch) -> R,
+    ) -> Result<R, OutOfOrderOffset> {
+        let next_offset = self.next_offset();
+        if offset < next_offset {
+            return Err(OutOfOrderOffset {
+                offset,
+                next_offset,
+            });
+        }
+        let revision = self.0.advance_to(Self::revision(offset));
+        Ok(f(&mut OffsetBatch { revision }))
+    }
+}
+
+#[derive(Debug)]
+pub struct OffsetBatch {
+    revision: Revision,
+}
+
+impl OffsetBatch {
+    pub fn modify(&mut self, last_modified: &mut LastModified) {
+        last_modified.0 = self.revision;
+    }
+}
+
+/// A point in the modification history. Revision `0` means dirty, i.e. older than anything, and
+/// `Current` starts at `1`.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Revision(u64);
 
 impl Revision {
-    const DIRTY: Revision = Revision(0);
+    /// Older than any revision handed out by a `Current`.
+    pub const DIRTY: Revision = Revision(0);
     const INITIAL_CURRENT: Revision = Revision(1);
+    pub const MAX: Revision = Revision(u64::MAX);
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Reconstructs a revision obtained from `as_u64`, e.g. from persistence or telemetry.
+    /// Revisions that were never handed out by a `Current` in this process break the ordering
+    /// that caching relies on; pass them to `Current::restored` after loading.
+    #[cfg(feature = "raw-revision")]
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    pub fn checked_increment(self) -> Option<Revision> {
+        self.0.checked_add(1).map(Revision)
+    }
+
+    /// Keeps the most recent of both revisions.
+    pub fn merge(&mut self, other: Revision) {
+        if *self < other {
+            *self = other;
+        }
+    }
+
+    /// Panics instead of wrapping around, which would make stale caches appear fresh.
+    fn increment(self) -> Revision {
+        self.checked_increment()
+            .expect("Revision overflow: cannot advance past Revision::MAX")
+    }
+}
+
+/// The most recent revision, `Revision::DIRTY` when there are none.
+pub fn max_revision(revisions: impl IntoIterator<Item = Revision>) -> Revision {
+    revisions.into_iter().max().unwrap_or(Revision::DIRTY)
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastVerified(Revision);
 
 impl LastVerified {
     pub fn clean(current: &Current) -> Self {
-        Self(current.0)
+        Self(current.revision)
     }
 
     pub fn dirty() -> Self {
         Self(Revision::DIRTY)
     }
 
+    /// The revision this was last verified at, e.g. to restore `Current` after loading.
+    pub fn revision(&self) -> Revision {
+        self.0
+    }
+
     pub fn should_verify(&self, current: &Current) -> bool {
-        self.0 < current.0
+        !self.is_fresh_at(current.revision)
+    }
+
+    /// Whether this would be verified if `revision` were the current revision.
+    pub fn is_fresh_at(&self, revision: Revision) -> bool {
+        self.0 >= revision
     }
 
     pub fn update_to(&mut self, current: &Current) {
-        debug_assert!(self.0 < current.0);
-        self.0 = current.0;
+        debug_assert!(
+            self.0 < current.revision,
+            "LastVerified::update_to: already verified at {:?}, current is {:?}",
+            self.0,
+            current.revision
+        );
+        self.0 = current.revision;
     }
 
-    pub fn verify_with(&mut self, current: &Current, f: impl FnOnce()) {
-        if self.should_verify(&current) {
-            self.update_to(&current);
-            f()
+    /// Runs `f` if not yet verified in this revision and returns its result.
+    pub fn verify_with<R>(&mut self, current: &Current, f: impl FnOnce() -> R) -> Option<R> {
+        if self.should_verify(current) {
+            self.update_to(current);
+            Some(f())
+        } else {
+            None
         }
     }
 }
 
+/// Decides the revision `Current` advances to on modification, see `Current::with_strategy`. The
+/// returned revision must be strictly greater than the current one.
+pub trait AdvanceStrategy {
+    fn advance(&mut self, current: u64) -> u64;
+}
+
+/// Advances by one, this is what `Current::new` uses.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Increment;
+
+impl AdvanceStrategy for Increment {
+    fn advance(&mut self, current: u64) -> u64 {
+        Revision(current).increment().0
+    }
+}
+
+/// Advances to the time reported by the clock, or by one if the clock did not move forward.
+#[derive(Debug)]
+pub struct Timestamp<C>(pub C);
+
+impl<C: FnMut() -> u64> AdvanceStrategy for Timestamp<C> {
+    fn advance(&mut self, current: u64) -> u64 {
+        std::cmp::max(Revision(current).increment().0, (self.0)())
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastModified(Revision);
 
 impl LastModified {
     pub fn new(current: &Current) -> Self {
-        Self(current.0)
+        Self(current.revision)
     }
 
     pub fn modify(&mut self, current: &mut Current) {
-        (current.0).0 += 1;
-        self.0 = current.0;
+        debug_assert!(
+            self.0 <= current.revision,
+            "LastModified::modify: last modified {:?} is ahead of current {:?}",
+            self.0,
+            current.revision
+        );
+        self.0 = current.advance();
+    }
+
+    /// Stores `new` in `value` and modifies only if it differs. Returns whether the revision
+    /// advanced.
+    pub fn set_if_changed<T: PartialEq>(
+        &mut self,
+        current: &mut Current,
+        value: &mut T,
+        new: T,
+    ) -> bool {
+        if *value == new {
+            return false;
+        }
+        *value = new;
+        self.modify(current);
+        true
+    }
+
+    /// Call after putting back the value from the snapshot. A leaf modified after the snapshot
+    /// is stamped with the restore revision, so everything computed from it recomputes.
+    pub fn rollback(&mut self, restore: Restore) {
+        if self.0 > restore.snapshot {
+            self.0 = restore.revision;
+        }
     }
 }
 
@@ -71,26 +549,56 @@ impl Dependee for LastModified {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastComputed(Revision);
 
 impl LastComputed {
     pub fn clean(current: &Current) -> Self {
-        Self(current.0)
+        Self(current.revision)
     }
 
     pub fn dirty() -> Self {
         Self(Revision::DIRTY)
     }
 
-    pub fn should_compute(&self, dependee: &impl Dependee) -> bool {
+    pub fn should_compute<D: Dependee + ?Sized>(&self, dependee: &D) -> bool {
         self.0 < dependee.revision()
     }
 
-    pub fn update_to(&mut self, dependee: &impl Dependee) {
+    pub fn update_to<D: Dependee + ?Sized>(&mut self, dependee: &D) {
         let revision = dependee.revision();
-        if self.0 < revision {
-            self.0 = revision
+        debug_assert!(
+            self.0 <= revision,
+            "LastComputed::update_to: dependee {:?} is behind last computed {:?}, use merge to fold \
+             several dependees",
+            revision,
+            self.0
+        );
+        self.0 = revision;
+    }
+
+    /// Takes the maximum of the current and the dependee revision, for folding several dependees.
+    pub fn merge<D: Dependee + ?Sized>(&mut self, dependee: &D) {
+        self.0.merge(dependee.revision());
+    }
+
+    /// Early cutoff: only updates to the dependee when the recomputed value differs from the old
+    /// one, so dependents of this revision do not recompute. Returns whether it updated.
+    ///
+    /// Because the revision stays behind the dependee when nothing changed, use it only as the
+    /// revision that dependents compare against and track the inputs with a separate
+    /// `LastComputed`.
+    pub fn update_to_if_changed<D: Dependee + ?Sized, T: PartialEq>(
+        &mut self,
+        dependee: &D,
+        new: &T,
+        old: &T,
+    ) -> bool {
+        if new == old {
+            return false;
         }
+        self.update_to(dependee);
+        true
     }
 }
 
@@ -99,3 +607,778 @@ impl Dependee for LastComputed {
         self.0
     }
 }
+
+/// A cached value together with the revisions needed to keep it up to date.
+#[derive(Debug)]
+pub struct Node<T> {
+    value: T,
+    last_verified: LastVerified,
+    last_computed: LastComputed,
+    poisoned: bool,
+    computation_count: u64,
+    // Treated as an extra dependee so `invalidate` forces a recomputation.
+    last_invalidated: Revision,
+}
+
+impl<T> Node<T> {
+    /// Creates a dirty node, `value` is only used until the first computation.
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            last_verified: LastVerified::dirty(),
+            last_computed: LastComputed::dirty(),
+            poisoned: false,
+            computation_count: 0,
+            last_invalidated: Revision::DIRTY,
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// The value together with the revision it was computed from, suitable as an ETag.
+    pub fn value_with_revision(&self) -> (&T, Revision) {
+        (&self.value, self.last_computed.revision())
+    }
+
+    /// Forces a recomputation on the next verify for changes the dependees do not capture, e.g.
+    /// external I/O. Advances `Current` so dependents of this node recompute as well.
+    pub fn invalidate(&mut self, current: &mut Current) {
+        self.last_invalidated = current.advance();
+    }
+
+    /// Whether the node would be verified if `revision` were the current revision, without
+    /// consulting `Current`.
+    pub fn is_fresh_at(&self, revision: Revision) -> bool {
+        self.last_verified.is_fresh_at(revision) && !self.poisoned
+    }
+
+    /// How many times `compute` ran to completion.
+    pub fn computation_count(&self) -> u64 {
+        self.computation_count
+    }
+
+    /// Whether a computation panicked and the value may be partially updated. A poisoned node is
+    /// never considered verified, so the next `verify` recomputes it.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Recomputes the value if it has not been verified in this revision and the dependees
+    /// changed since the last computation, or if it was never computed. The dependees must have
+    /// been verified before calling this. If `compute` panics the node is left poisoned.
+    ///
+    /// The dependees may change between calls, e.g. when a computation starts reading an extra
+    /// input. The last computed revision is the maximum over the dependees passed in the call that
+    /// computed, so a dependee added later invalidates the node as soon as it is modified.
+    pub fn verify<D: Dependee + ?Sized>(
+        &mut self,
+        current: &Current,
+        dependees: &D,
+        compute: impl FnOnce(&mut T),
+    ) -> &T {
+        if self.last_verified.should_verify(current) {
+            let mut revision = dependees.revision();
+            revision.merge(self.last_invalidated);
+            if self.computation_count == 0 || self.poisoned || self.last_computed.0 < revision {
+                // Only record the revisions once compute returns so a panic leaves the node
+                // dirty and the next verify recomputes.
+                self.poisoned = true;
+                compute(&mut self.value);
+                self.poisoned = false;
+                self.computation_count += 1;
+                self.last_computed.0 = revision;
+            }
+
+            self.last_verified.update_to(current);
+        }
+        &self.value
+    }
+}
+
+impl<T> Dependee for Node<T> {
+    fn revision(&self) -> Revision {
+        self.last_computed.revision()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "LastVerified::update_to: already verified")]
+    fn last_verified_update_to_must_move_forward() {
+        let current = Current::new();
+        let mut last_verified = LastVerified::clean(&current);
+        last_verified.update_to(&current);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "LastModified::modify: last modified")]
+    fn last_modified_cannot_be_ahead_of_current() {
+        let mut current = Current::new();
+        let mut last_modified = LastModified::new(&current);
+        last_modified.modify(&mut current);
+        last_modified.modify(&mut Current::new());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "LastComputed::update_to: dependee")]
+    fn last_computed_update_to_must_move_forward() {
+        let mut current = Current::new();
+        let old = LastModified::new(&current);
+        let mut new = LastModified::new(&current);
+        new.modify(&mut current);
+        let mut last_computed = LastComputed::dirty();
+        last_computed.update_to(&new);
+        last_computed.update_to(&old);
+    }
+
+    #[test]
+    fn last_computed_merge_keeps_the_maximum() {
+        let mut current = Current::new();
+        let old = LastModified::new(&current);
+        let mut new = LastModified::new(&current);
+        new.modify(&mut current);
+        let mut last_computed = LastComputed::dirty();
+        last_computed.merge(&new);
+        last_computed.merge(&old);
+        assert_eq!(last_computed.revision(), new.revision());
+    }
+
+    #[test]
+    fn offset_current_accepts_increasing_offsets() {
+        let mut current = OffsetCurrent::new();
+        let mut a = LastModified::new(current.current());
+        let mut b = LastModified::new(current.current());
+        let mut last_verified = LastVerified::dirty();
+        let mut last_computed = LastComputed::dirty();
+
+        // Message queue offsets start at zero.
+        current.modify(&mut a, 0).unwrap();
+        assert!(last_computed.should_compute(&a));
+        last_verified.update_to(current.current());
+        last_computed.update_to(&a);
+
+        current.modify(&mut a, 5).unwrap();
+        assert!(last_verified.should_verify(current.current()));
+        last_verified.update_to(current.current());
+        last_computed.update_to(&a);
+
+        current.modify(&mut b, 7).unwrap();
+        assert!(last_verified.should_verify(current.current()));
+        assert!(last_computed.should_compute(&b));
+        last_verified.update_to(current.current());
+        last_computed.update_to(&b);
+
+        current.modify(&mut a, 10).unwrap();
+        assert!(last_computed.should_compute(&a));
+        assert!(!last_computed.should_compute(&b));
+        assert_eq!(current.next_offset(), 11);
+    }
+
+    #[test]
+    fn offset_current_rejects_out_of_order_offsets() {
+        let mut current = OffsetCurrent::new();
+        let mut a = LastModified::new(current.current());
+        current.modify(&mut a, 7).unwrap();
+        let stamp = a.revision();
+
+        let error = current.modify(&mut a, 5).unwrap_err();
+        assert_eq!(
+            error,
+            OutOfOrderOffset {
+                offset: 5,
+                next_offset: 8
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "offset 5 is behind the next expected offset 8"
+        );
+        assert_eq!(current.modify(&mut a, 7).unwrap_err().offset, 7);
+        assert_eq!(a.revision(), stamp);
+    }
+
+    #[test]
+    fn offset_current_batch_stamps_several_inputs() {
+        let mut current = OffsetCurrent::new();
+        let mut a = LastModified::new(current.current());
+        let mut b = LastModified::new(current.current());
+        current
+            .batch(5, |batch| {
+                batch.modify(&mut a);
+                batch.modify(&mut b);
+            })
+            .unwrap();
+        assert_eq!(a.revision(), b.revision());
+        assert_eq!(current.next_offset(), 6);
+    }
+
+    #[test]
+    fn offset_current_resumes_a_replay() {
+        let mut current = OffsetCurrent::resume_at(100);
+        let mut a = LastModified::new(current.current());
+        assert_eq!(current.next_offset(), 100);
+        assert_eq!(current.modify(&mut a, 99).unwrap_err().next_offset, 100);
+        current.modify(&mut a, 100).unwrap();
+
+        assert_eq!(OffsetCurrent::resume_at(0).next_offset(), 0);
+    }
+
+    #[test]
+    fn node_sum_a_b() {
+        let mut current = Current::new();
+        let (mut a, mut a_modified) = (1, LastModified::new(&current));
+        let (b, b_modified) = (2, LastModified::new(&current));
+        let mut sum = Node::new(0);
+
+        assert_eq!(
+            *sum.verify(&current, &(&a_modified, &b_modified), |sum| *sum = a + b),
+            3
+        );
+        sum.verify(&current, &(&a_modified, &b_modified), |_| unreachable!());
+
+        let mut unrelated = LastModified::new(&current);
+        unrelated.modify(&mut current);
+        sum.verify(&current, &(&a_modified, &b_modified), |_| unreachable!());
+
+        a = 5;
+        a_modified.modify(&mut current);
+        assert_eq!(
+            *sum.verify(&current, &(&a_modified, &b_modified), |sum| *sum = a + b),
+            7
+        );
+    }
+
+    #[test]
+    fn node_without_dependees_computes_once() {
+        let mut current = Current::new();
+        let mut node = Node::new(0);
+        let no_dependees = LastComputed::dirty();
+        assert_eq!(
+            *node.verify(&current, &no_dependees, |value| *value = 42),
+            42
+        );
+
+        let mut unrelated = LastModified::new(&current);
+        unrelated.modify(&mut current);
+        node.verify(&current, &no_dependees, |_| unreachable!());
+    }
+
+    #[test]
+    fn node_recomputes_after_a_panic() {
+        let mut current = Current::new();
+        let mut input = LastModified::new(&current);
+        let mut node = Node::new(0);
+        node.verify(&current, &input, |value| *value = 1);
+
+        input.modify(&mut current);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            node.verify(&current, &input, |value| {
+                *value = -1;
+                panic!("compute failed");
+            });
+        }));
+        assert!(result.is_err());
+        assert!(node.is_poisoned());
+
+        assert_eq!(*node.verify(&current, &input, |value| *value = 2), 2);
+        assert!(!node.is_poisoned());
+        node.verify(&current, &input, |_| unreachable!());
+    }
+
+    #[test]
+    fn current_advances_with_its_strategy() {
+        let clock = std::sync::Arc::new(AtomicU64::new(100));
+        let mut current = Current::with_strategy(Timestamp({
+            let clock = clock.clone();
+            move || clock.load(Ordering::SeqCst)
+        }));
+        let mut a = LastModified::new(&current);
+
+        a.modify(&mut current);
+        assert_eq!(a.revision(), Revision(100));
+
+        // The clock did not move, so the strategy falls back to incrementing.
+        a.modify(&mut current);
+        assert_eq!(a.revision(), Revision(101));
+
+        clock.store(200, Ordering::SeqCst);
+        a.modify(&mut current);
+        assert_eq!(a.revision(), Revision(200));
+    }
+
+    struct Stuck;
+
+    impl AdvanceStrategy for Stuck {
+        fn advance(&mut self, current: u64) -> u64 {
+            current
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "AdvanceStrategy::advance: next revision")]
+    fn advance_strategy_must_move_forward() {
+        let mut current = Current::with_strategy(Stuck);
+        LastModified::new(&current).modify(&mut current);
+    }
+
+    #[test]
+    fn node_tracks_a_dependee_added_on_a_later_computation() {
+        let mut current = Current::new();
+        let mut flag = LastModified::new(&current);
+        let mut extra = LastModified::new(&current);
+        let mut node = Node::new(0);
+        // The first computation only reads `flag`.
+        node.verify(&current, &flag, |value| *value = 1);
+
+        // The second computation starts reading `extra` as well.
+        flag.modify(&mut current);
+        node.verify(&current, &(&flag, &extra), |value| *value = 2);
+
+        extra.modify(&mut current);
+        assert_eq!(
+            *node.verify(&current, &(&flag, &extra), |value| *value = 3),
+            3
+        );
+    }
+
+    #[test]
+    fn tagged_current_reports_the_tag_of_modifications() {
+        let mut current = TaggedCurrent::default();
+        let mut input = LastModified::new(current.current());
+        let mut node = Node::new(());
+        assert_eq!(current.tag(input.revision()), None);
+
+        current.advance_with_tag(1000);
+        input.modify(current.current_mut());
+        node.verify(current.current(), &input, |_| {});
+        assert_eq!(current.tag(input.revision()), Some(1000));
+        assert_eq!(current.tag(node.revision()), Some(1000));
+
+        current.advance_with_tag(1001);
+        assert_eq!(current.tag(node.revision()), Some(1000));
+        input.modify(current.current_mut());
+        input.modify(current.current_mut());
+        assert_eq!(current.tag(input.revision()), Some(1001));
+    }
+
+    #[test]
+    fn tagged_current_forgets_old_tags() {
+        let mut current = TaggedCurrent::default();
+        let first = current.advance_with_tag(1000);
+        for tag in 0..TAG_CAPACITY as u64 - 1 {
+            current.advance_with_tag(tag);
+        }
+        assert_eq!(current.tag(first), Some(1000));
+        current.advance_with_tag(2000);
+        assert_eq!(current.tag(first), None);
+    }
+
+    #[test]
+    fn revision_checked_increment_stops_at_max() {
+        let before_max = Revision(u64::MAX - 1);
+        assert_eq!(before_max.checked_increment(), Some(Revision::MAX));
+        assert_eq!(Revision::MAX.checked_increment(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Revision overflow")]
+    fn current_panics_instead_of_wrapping() {
+        let mut current = Current {
+            revision: Revision(u64::MAX - 1),
+            ..Current::new()
+        };
+        let mut last_modified = LastModified::new(&current);
+        last_modified.modify(&mut current);
+        assert_eq!(last_modified.revision(), Revision::MAX);
+        last_modified.modify(&mut current);
+    }
+
+    #[test]
+    fn atomic_current_hands_out_unique_revisions() {
+        let current = AtomicCurrent::new();
+        let modify = || {
+            let mut last_modified = LastModified::new(&current.load());
+            (0..1000)
+                .map(|_| {
+                    current.modify(&mut last_modified);
+                    last_modified.revision()
+                })
+                .collect::<Vec<_>>()
+        };
+        let (mut a, b) = std::thread::scope(|scope| {
+            let a = scope.spawn(modify);
+            let b = scope.spawn(modify);
+            (a.join().unwrap(), b.join().unwrap())
+        });
+
+        a.extend(b);
+        a.sort();
+        a.dedup();
+        assert_eq!(a.len(), 2000);
+        assert_eq!(max_revision(a), current.revision());
+    }
+
+    #[test]
+    #[should_panic(expected = "Revision overflow")]
+    fn atomic_current_panics_instead_of_wrapping() {
+        let current = AtomicCurrent(AtomicU64::new(u64::MAX));
+        current.modify(&mut LastModified::new(&current.load()));
+    }
+
+    #[test]
+    fn node_value_with_revision_changes_with_the_value() {
+        let mut current = Current::new();
+        let mut input = LastModified::new(&current);
+        let mut node = Node::new(0);
+        node.verify(&current, &input, |value| *value = 1);
+        let (&value, etag) = node.value_with_revision();
+        assert_eq!((value, etag), (1, input.revision()));
+
+        let mut unrelated = LastModified::new(&current);
+        unrelated.modify(&mut current);
+        node.verify(&current, &input, |_| unreachable!());
+        assert_eq!(node.value_with_revision().1, etag);
+
+        input.modify(&mut current);
+        node.verify(&current, &input, |value| *value = 2);
+        assert_eq!(node.value_with_revision(), (&2, input.revision()));
+    }
+
+    #[test]
+    fn last_computed_update_to_if_changed_cuts_off_downstream() {
+        let mut current = Current::new();
+        let mut a_modified = LastModified::new(&current);
+
+        // half = a / 2 tracks its input and its output separately.
+        let mut half = 0;
+        let mut half_inputs = LastComputed::dirty();
+        let mut half_output = LastComputed::dirty();
+        let mut downstream = LastComputed::dirty();
+
+        for &(a, half_changed) in &[(4, true), (5, false)] {
+            a_modified.modify(&mut current);
+            assert!(half_inputs.should_compute(&a_modified));
+            half_inputs.update_to(&a_modified);
+            let old = std::mem::replace(&mut half, a / 2);
+            assert_eq!(
+                half_output.update_to_if_changed(&a_modified, &half, &old),
+                half_changed
+            );
+            assert_eq!(downstream.should_compute(&half_output), half_changed);
+            downstream.merge(&half_output);
+        }
+        assert_eq!(half, 2);
+    }
+
+    #[test]
+    fn dependee_collections_take_the_most_recent_revision() {
+        let mut current = Current::new();
+        let a = LastModified::new(&current);
+        let mut b = LastModified::new(&current);
+        b.modify(&mut current);
+
+        assert_eq!((&a, &b).revision(), b.revision());
+        assert_eq!((&b, &a, &a).revision(), b.revision());
+        assert_eq!([&a, &b][..].revision(), b.revision());
+        let dyn_dependees: [&dyn Dependee; 2] = [&b, &a];
+        assert_eq!(dyn_dependees[..].revision(), b.revision());
+        assert_eq!((&&a).revision(), a.revision());
+
+        let empty: &[LastModified] = &[];
+        assert_eq!(empty.revision(), Revision::DIRTY);
+    }
+
+    #[test]
+    fn revision_merge_and_max_revision_keep_the_most_recent() {
+        let mut revision = Revision(3);
+        revision.merge(Revision(2));
+        assert_eq!(revision, Revision(3));
+        revision.merge(Revision(5));
+        assert_eq!(revision, Revision(5));
+
+        assert_eq!(
+            max_revision(vec![Revision(2), Revision(7), Revision(4)]),
+            Revision(7)
+        );
+        assert_eq!(max_revision(None), Revision::DIRTY);
+    }
+
+    #[test]
+    fn node_depending_on_current_recomputes_every_revision() {
+        let mut current = Current::new();
+        let mut frame = Node::new(0);
+        let mut downstream = Node::new(0);
+        let mut unrelated = LastModified::new(&current);
+
+        for _ in 0..3 {
+            let revision = current.revision().0;
+            frame.verify(&current, &current, |frame| *frame = revision);
+            assert_eq!(frame.revision(), current.revision());
+            let frame_value = *frame.value();
+            downstream.verify(&current, &frame, |value| *value = frame_value);
+            assert_eq!(*downstream.value(), revision);
+            unrelated.modify(&mut current);
+        }
+    }
+
+    #[test]
+    fn restored_current_is_never_behind_loaded_revisions() {
+        let mut current = Current::with_strategy(Timestamp(|| 1000));
+        let mut a = LastModified::new(&current);
+        let mut b = LastModified::new(&current);
+        a.modify(&mut current);
+        b.modify(&mut current);
+        let mut last_verified = LastVerified::dirty();
+        last_verified.update_to(&current);
+        let loaded = [a.revision(), b.revision(), last_verified.revision()];
+
+        let mut current = Current::with_strategy(Timestamp(|| 500)).restored(loaded.to_vec());
+        for &revision in &loaded {
+            assert!(revision <= current.revision());
+        }
+        assert!(!last_verified.should_verify(&current));
+
+        // The strategy survives restoring, and the next modification is ahead of everything.
+        a.modify(&mut current);
+        assert_eq!(a.revision(), Revision(1002));
+        assert!(last_verified.should_verify(&current));
+
+        assert_eq!(
+            Current::new().restored(None).revision(),
+            Revision::INITIAL_CURRENT
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip_keeps_caches_fresh() {
+        let mut current = Current::new();
+        let mut input = LastModified::new(&current);
+        input.modify(&mut current);
+        let mut last_verified = LastVerified::dirty();
+        let mut last_computed = LastComputed::dirty();
+        last_verified.update_to(&current);
+        last_computed.update_to(&input);
+
+        let json = serde_json::to_string(&(&input, &last_verified, &last_computed)).unwrap();
+        let (mut input, last_verified, last_computed): (LastModified, LastVerified, LastComputed) =
+            serde_json::from_str(&json).unwrap();
+
+        let mut current = Current::new().restored(vec![
+            input.revision(),
+            last_verified.revision(),
+            last_computed.revision(),
+        ]);
+        assert!(!last_verified.should_verify(&current));
+        assert!(!last_computed.should_compute(&input));
+
+        input.modify(&mut current);
+        assert!(last_verified.should_verify(&current));
+        assert!(last_computed.should_compute(&input));
+    }
+
+    #[test]
+    fn last_modified_set_if_changed_skips_equal_values() {
+        let mut current = Current::new();
+        let mut value = 1;
+        let mut last_modified = LastModified::new(&current);
+
+        assert!(!last_modified.set_if_changed(&mut current, &mut value, 1));
+        assert_eq!(current.revision(), Revision::INITIAL_CURRENT);
+
+        assert!(last_modified.set_if_changed(&mut current, &mut value, 2));
+        assert_eq!(value, 2);
+        assert_eq!(last_modified.revision(), current.revision());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Batch::modify: last modified")]
+    fn batch_modify_cannot_be_ahead_of_current() {
+        let mut current = Current::new();
+        let mut last_modified = LastModified::new(&current);
+        last_modified.modify(&mut current);
+        Current::new().batch(|batch| batch.modify(&mut last_modified));
+    }
+
+    #[test]
+    fn batch_advances_current_once() {
+        let mut current = Current::new();
+        let mut a = LastModified::new(&current);
+        let mut b = LastModified::new(&current);
+        let mut sum = Node::new(());
+        sum.verify(&current, &(&a, &b), |_| {});
+        assert_eq!(sum.computation_count(), 1);
+        let before = current.revision();
+
+        current.batch(|batch| {
+            batch.modify(&mut a);
+            batch.modify(&mut b);
+            batch.modify(&mut a);
+        });
+        assert_eq!(current.revision(), before.checked_increment().unwrap());
+        assert_eq!(a.revision(), current.revision());
+        assert_eq!(b.revision(), current.revision());
+
+        // One recomputation for the whole batch.
+        sum.verify(&current, &(&a, &b), |_| {});
+        assert_eq!(sum.computation_count(), 2);
+
+        current.batch(|_| {});
+        assert_eq!(a.revision(), current.revision());
+    }
+
+    #[test]
+    fn current_notifies_observers_once_per_modification() {
+        use std::sync::Arc;
+
+        let advances = Arc::new(AtomicU64::new(0));
+        let mut current = Current::new();
+        current.on_advance({
+            let advances = Arc::clone(&advances);
+            Box::new(move |_| {
+                advances.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        let (mut a, mut a_modified) = (1, LastModified::new(&current));
+        let mut b_modified = LastModified::new(&current);
+
+        a_modified.modify(&mut current);
+        assert_eq!(advances.load(Ordering::SeqCst), 1);
+
+        assert!(!a_modified.set_if_changed(&mut current, &mut a, 1));
+        assert_eq!(advances.load(Ordering::SeqCst), 1);
+
+        assert!(a_modified.set_if_changed(&mut current, &mut a, 2));
+        assert_eq!(advances.load(Ordering::SeqCst), 2);
+
+        current.batch(|batch| {
+            batch.modify(&mut a_modified);
+            batch.modify(&mut b_modified);
+        });
+        assert_eq!(advances.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn restore_and_rollback_recompute_from_restored_values() {
+        let mut current = Current::new();
+        let (mut a, mut a_modified) = (1, LastModified::new(&current));
+        let (b, b_modified) = (10, LastModified::new(&current));
+        let mut double = Node::new(0);
+        let mut sum = Node::new(0);
+        double.verify(&current, &a_modified, |double| *double = 2 * a);
+        sum.verify(&current, &b_modified, |sum| *sum = b);
+
+        let snapshot = current.snapshot();
+        a = 2;
+        a_modified.modify(&mut current);
+        double.verify(&current, &a_modified, |double| *double = 2 * a);
+        assert_eq!(*double.value(), 4);
+        let before_restore = current.revision();
+
+        a = 1;
+        let restore = current.restore(snapshot);
+        a_modified.rollback(restore);
+        assert!(current.revision() > before_restore);
+        assert_eq!(a_modified.revision(), current.revision());
+
+        // The dependent is not rolled back, the restored leaf makes it recompute.
+        assert_eq!(
+            *double.verify(&current, &a_modified, |double| *double = 2 * a),
+            2
+        );
+
+        // A dependent of a leaf left alone since the snapshot stays cached.
+        let mut recomputed = false;
+        sum.verify(&current, &b_modified, |_| recomputed = true);
+        assert!(!recomputed);
+    }
+
+    #[test]
+    fn node_computation_count_counts_only_computations() {
+        let mut current = Current::new();
+        let mut input = LastModified::new(&current);
+        let mut unrelated = LastModified::new(&current);
+        let mut node = Node::new(());
+        assert_eq!(node.computation_count(), 0);
+
+        for _ in 0..3 {
+            input.modify(&mut current);
+            node.verify(&current, &input, |_| {});
+            unrelated.modify(&mut current);
+            node.verify(&current, &input, |_| {});
+        }
+        assert_eq!(node.computation_count(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "raw-revision")]
+    fn revision_from_raw_round_trips() {
+        let mut current = Current::new();
+        let mut last_modified = LastModified::new(&current);
+        last_modified.modify(&mut current);
+        let raw = last_modified.revision().as_u64();
+        assert_eq!(Revision::from_raw(raw), last_modified.revision());
+    }
+
+    #[test]
+    fn last_verified_verify_with_runs_once_per_revision() {
+        let mut current = Current::new();
+        let mut last_verified = LastVerified::dirty();
+        assert_eq!(last_verified.verify_with(&current, || 1), Some(1));
+        assert_eq!(last_verified.verify_with(&current, || 2), None);
+
+        LastModified::new(&current).modify(&mut current);
+        assert_eq!(last_verified.verify_with(&current, || 3), Some(3));
+    }
+
+    #[test]
+    fn is_fresh_at_compares_without_current() {
+        let mut current = Current::new();
+        let mut node = Node::new(());
+        node.verify(&current, &LastComputed::dirty(), |_| {});
+        let verified_at = current.revision();
+        let next = verified_at.checked_increment().unwrap();
+
+        assert!(node.is_fresh_at(Revision::DIRTY));
+        assert!(node.is_fresh_at(verified_at));
+        assert!(!node.is_fresh_at(next));
+
+        LastModified::new(&current).modify(&mut current);
+        assert!(node.is_fresh_at(verified_at));
+        assert!(LastVerified::dirty().is_fresh_at(Revision::DIRTY));
+    }
+
+    #[test]
+    fn node_invalidate_recomputes_node_and_dependents() {
+        let mut current = Current::new();
+        let input = LastModified::new(&current);
+        let mut external = Node::new(0);
+        let mut dependent = Node::new(0);
+        external.verify(&current, &input, |value| *value = 1);
+        dependent.verify(&current, &external, |_| {});
+
+        external.invalidate(&mut current);
+        external.verify(&current, &input, |value| *value = 2);
+        let value = *external.value();
+        dependent.verify(&current, &external, |dependent| *dependent = value);
+        assert_eq!(*dependent.value(), 2);
+        assert_eq!(external.computation_count(), 2);
+        assert_eq!(dependent.computation_count(), 2);
+
+        // Invalidating once recomputes once.
+        LastModified::new(&current).modify(&mut current);
+        external.verify(&current, &input, |_| {});
+        dependent.verify(&current, &external, |_| {});
+        assert_eq!(external.computation_count(), 2);
+        assert_eq!(dependent.computation_count(), 2);
+    }
+}